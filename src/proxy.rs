@@ -1,15 +1,95 @@
-use httparse::{Response, EMPTY_HEADER};
+use httparse::{Response, Status, EMPTY_HEADER};
 use mio::net::TcpStream;
 use result::{Error, Kind, Result};
 use std::io::{Read, Write};
 use std::net::TcpStream as StdTcpStream;
 use url::Url;
 
+/// Upper bound on an accumulated proxy response, to guard against a
+/// misbehaving (or malicious) proxy streaming unbounded headers.
+const MAX_PROXY_RESPONSE_BYTES: usize = 64 * 1024;
+
+/// Status/headers of a proxy's HTTP response, surfaced to middlewares so
+/// they can actually observe what the proxy sent back.
+#[derive(Debug, Clone)]
+pub struct ConnectResponse {
+    pub code: u16,
+    pub headers: Vec<(String, String)>,
+}
+
+/// Reads a full HTTP response off `stream`, looping over short reads and
+/// re-parsing until `httparse` reports the headers are complete, rather than
+/// assuming a single `read` call returns the whole thing.
+fn read_proxy_response<R: Read>(stream: &mut R) -> Result<ConnectResponse> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0; 1024];
+
+    loop {
+        let n = stream.read(&mut chunk)?;
+        if n == 0 {
+            return Err(Error::new(
+                Kind::Proxy(None),
+                "proxy closed the connection before sending a complete response.",
+            ));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+
+        if buf.len() > MAX_PROXY_RESPONSE_BYTES {
+            return Err(Error::new(
+                Kind::Proxy(None),
+                "proxy response exceeded the maximum allowed size.",
+            ));
+        }
+
+        let mut headers = [EMPTY_HEADER; 32];
+        let mut res = Response::new(&mut headers);
+
+        match res.parse(&buf)? {
+            Status::Partial => continue,
+            Status::Complete(_) => {
+                let code = res
+                    .code
+                    .ok_or_else(|| Error::new(Kind::Proxy(None), "proxy response missing a status code."))?;
+                let headers = res
+                    .headers
+                    .iter()
+                    .map(|header| {
+                        (
+                            header.name.to_string(),
+                            String::from_utf8_lossy(header.value).into_owned(),
+                        )
+                    })
+                    .collect();
+
+                return Ok(ConnectResponse { code, headers });
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProxyScheme {
+    Http,
+    Socks4,
+    Socks5,
+}
+
+impl<'a> From<&'a str> for ProxyScheme {
+    fn from(s: &'a str) -> Self {
+        match s.to_lowercase().as_ref() {
+            "socks4" | "socks4a" => ProxyScheme::Socks4,
+            "socks5" | "socks5h" => ProxyScheme::Socks5,
+            _ => ProxyScheme::Http,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum AuthType {
     None,
     Basic,
     Digest,
+    Bearer,
     Unknown(String),
 }
 
@@ -18,12 +98,128 @@ impl AuthType {
         match self {
             AuthType::None => String::from(auth),
             AuthType::Basic => crate::handshake::encode_base64(auth.as_bytes()),
-            AuthType::Digest => unimplemented!(),
+            AuthType::Digest => String::from(auth),
+            AuthType::Bearer => String::from(auth),
             AuthType::Unknown(_) => String::from(auth),
         }
     }
 }
 
+#[derive(Debug, Default)]
+struct DigestParams {
+    realm: String,
+    nonce: String,
+    qop: Option<String>,
+    opaque: Option<String>,
+    algorithm: Option<String>,
+}
+
+fn parse_digest_challenge(challenge: &str) -> DigestParams {
+    let mut params = DigestParams::default();
+
+    let body = match challenge.find(' ') {
+        Some(idx) => &challenge[idx + 1..],
+        None => "",
+    };
+
+    let mut parts = Vec::new();
+    let mut in_quotes = false;
+    let mut start = 0;
+    for (i, c) in body.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => {
+                parts.push(&body[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&body[start..]);
+
+    for part in parts {
+        let part = part.trim();
+        let idx = match part.find('=') {
+            Some(idx) => idx,
+            None => continue,
+        };
+        let key = part[..idx].trim().to_lowercase();
+        let value = part[idx + 1..].trim().trim_matches('"').to_string();
+
+        match key.as_ref() {
+            "realm" => params.realm = value,
+            "nonce" => params.nonce = value,
+            "qop" => params.qop = Some(value),
+            "opaque" => params.opaque = Some(value),
+            "algorithm" => params.algorithm = Some(value),
+            _ => {}
+        }
+    }
+
+    params
+}
+
+fn md5_hex(data: &[u8]) -> String {
+    format!("{:x}", md5::compute(data))
+}
+
+fn generate_cnonce() -> String {
+    let bytes: [u8; 8] = rand::random();
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn build_digest_authorization(
+    username: &str,
+    password: &str,
+    uri: &str,
+    params: &DigestParams,
+) -> Result<String> {
+    match params.algorithm.as_ref().map(|a| a.to_lowercase()).as_deref() {
+        None | Some("md5") => {}
+        _ => {
+            return Err(Error::new(Kind::Proxy(None), "unsupported digest algorithm."));
+        }
+    }
+
+    let ha1 = md5_hex(format!("{}:{}:{}", username, params.realm, password).as_bytes());
+    let ha2 = md5_hex(format!("CONNECT:{}", uri).as_bytes());
+
+    let mut header = format!(
+        "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\"",
+        username, params.realm, params.nonce, uri
+    );
+
+    let offers_auth_qop = params
+        .qop
+        .as_ref()
+        .map(|qop| qop.split(',').any(|token| token.trim() == "auth"))
+        .unwrap_or(false);
+
+    let response = if offers_auth_qop {
+        let cnonce = generate_cnonce();
+        let nc = "00000001";
+        let response =
+            md5_hex(format!("{}:{}:{}:{}:auth:{}", ha1, params.nonce, nc, cnonce, ha2).as_bytes());
+        header.push_str(&format!(", qop=auth, nc={}, cnonce=\"{}\"", nc, cnonce));
+        response
+    } else if params.qop.is_some() {
+        return Err(Error::new(
+            Kind::Proxy(None),
+            "proxy only offered unsupported qop options.",
+        ));
+    } else {
+        md5_hex(format!("{}:{}:{}", ha1, params.nonce, ha2).as_bytes())
+    };
+
+    header.push_str(&format!(", response=\"{}\"", response));
+
+    if let Some(opaque) = &params.opaque {
+        header.push_str(&format!(", opaque=\"{}\"", opaque));
+    }
+
+    Ok(header)
+}
+
 impl<'a> From<&'a str> for AuthType {
     fn from(s: &'a str) -> Self {
         let lower = s.to_lowercase();
@@ -36,26 +232,127 @@ impl<'a> From<&'a str> for AuthType {
             return AuthType::Digest;
         }
 
+        if lower.starts_with("bearer") {
+            return AuthType::Bearer;
+        }
+
         return AuthType::Unknown(String::from(s));
     }
 }
 
+/// The target of a `Proxy::connect` call, threaded through the middleware
+/// chain so middlewares can observe (and, by constructing a new one, modify)
+/// it before the underlying CONNECT is sent. `host` is what ends up in the
+/// CONNECT request line and `Host:` header when the active scheme speaks
+/// HTTP; SOCKS4/SOCKS5 resolve the target from `url` directly and ignore it.
+#[derive(Debug, Clone)]
+pub struct ConnectRequest {
+    pub url: Url,
+    pub host: String,
+}
+
+impl ConnectRequest {
+    fn new(url: Url, host: String) -> Self {
+        ConnectRequest { url, host }
+    }
+}
+
+/// What running the middleware chain produces: the established stream plus
+/// whatever response the proxy sent back. `response` is only populated for
+/// the HTTP CONNECT scheme; SOCKS4/SOCKS5 have no equivalent and leave it `None`.
+pub struct ConnectOutcome {
+    pub stream: TcpStream,
+    pub response: Option<ConnectResponse>,
+}
+
+/// A single link in the proxy connection middleware chain. Implementations
+/// can run logic before and/or after calling `next.run(request)`, e.g. extra
+/// logging, retry/backoff, or injecting a `Proxy-Authorization` token, and can
+/// inspect the `ConnectOutcome` that comes back from the rest of the chain.
+pub trait Middleware {
+    fn handle(&self, request: ConnectRequest, next: Next) -> Result<ConnectOutcome>;
+}
+
+/// Drives the remaining middlewares in the chain, falling back to the real
+/// TCP/CONNECT once the list is exhausted.
+#[derive(Clone, Copy)]
+pub struct Next<'a> {
+    middlewares: &'a [Box<dyn Middleware>],
+    proxy: &'a Proxy,
+}
+
+impl<'a> Next<'a> {
+    pub fn new(middlewares: &'a [Box<dyn Middleware>], proxy: &'a Proxy) -> Self {
+        Next { middlewares, proxy }
+    }
+
+    pub fn run(&self, request: ConnectRequest) -> Result<ConnectOutcome> {
+        match self.middlewares {
+            [head, tail @ ..] => head.handle(request, Next::new(tail, self.proxy)),
+            [] => self.proxy.connect_request(&request),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Proxy {
     url: Url,
     auth_type: AuthType,
+    scheme: ProxyScheme,
+    auto_auth: bool,
+    token: Option<String>,
 }
 
 impl Proxy {
     pub fn new(url: Url) -> Self {
+        let scheme = ProxyScheme::from(url.scheme());
         Proxy {
             url,
             auth_type: AuthType::None,
+            scheme,
+            auto_auth: false,
+            token: None,
         }
     }
 
     pub fn new_with_auth(url: Url, auth_type: AuthType) -> Self {
-        Proxy { url, auth_type }
+        let scheme = ProxyScheme::from(url.scheme());
+        Proxy {
+            url,
+            auth_type,
+            scheme,
+            auto_auth: false,
+            token: None,
+        }
+    }
+
+    /// Convenience constructor for token-gated proxies, since a bearer token
+    /// doesn't fit the `username:password` URL model `get_auth` assumes.
+    pub fn new_with_token(url: Url, token: &str) -> Self {
+        let scheme = ProxyScheme::from(url.scheme());
+        Proxy {
+            url,
+            auth_type: AuthType::Bearer,
+            scheme,
+            auto_auth: false,
+            token: Some(String::from(token)),
+        }
+    }
+
+    pub fn set_token(&mut self, token: &str) {
+        self.auth_type = AuthType::Bearer;
+        self.token = Some(String::from(token));
+    }
+
+    pub fn set_scheme(&mut self, scheme: ProxyScheme) {
+        self.scheme = scheme;
+    }
+
+    /// When set, a 407 response that offers multiple `Proxy-Authenticate`
+    /// schemes is retried automatically with the strongest one the stored
+    /// credentials support, instead of failing with `Kind::Proxy(Some(auth_list))`.
+    pub fn set_auto_auth(&mut self, auto_auth: bool) {
+        self.auto_auth = auto_auth;
     }
 
     pub fn set_auth_type(&mut self, auth_type: AuthType) {
@@ -79,13 +376,187 @@ impl Proxy {
     }
 
     pub fn connect(&self, url: &Url) -> Result<TcpStream> {
+        self.connect_outcome(url).map(|outcome| outcome.stream)
+    }
+
+    fn connect_outcome(&self, url: &Url) -> Result<ConnectOutcome> {
+        match self.scheme {
+            ProxyScheme::Socks4 => self.connect_socks4(url),
+            ProxyScheme::Socks5 => self.connect_socks5(url),
+            ProxyScheme::Http => self.connect_http(url),
+        }
+    }
+
+    /// Dispatches a `ConnectRequest` from the middleware chain, honoring the
+    /// (possibly middleware-edited) `host` for the HTTP CONNECT scheme.
+    fn connect_request(&self, request: &ConnectRequest) -> Result<ConnectOutcome> {
+        match self.scheme {
+            ProxyScheme::Socks4 => self.connect_socks4(&request.url),
+            ProxyScheme::Socks5 => self.connect_socks5(&request.url),
+            ProxyScheme::Http => self.connect_http_with_host(&request.url, &request.host),
+        }
+    }
+
+    /// Same as `connect`, but runs the CONNECT step through `middlewares`
+    /// first, in order, terminating on the real connection attempt once the
+    /// chain is exhausted.
+    pub fn connect_with_middlewares(
+        &self,
+        url: &Url,
+        middlewares: &[Box<dyn Middleware>],
+    ) -> Result<TcpStream> {
+        let host = format!(
+            "{}:{}",
+            url.host_str().unwrap(),
+            url.port_or_known_default().unwrap_or(80)
+        );
+        let request = ConnectRequest::new(url.clone(), host);
+        Next::new(middlewares, self).run(request).map(|outcome| outcome.stream)
+    }
+
+    fn connect_socks5(&self, url: &Url) -> Result<ConnectOutcome> {
+        let mut stream = StdTcpStream::connect(&self.url)?;
+
+        let methods: &[u8] = if self.has_auth() {
+            &[0x00, 0x02]
+        } else {
+            &[0x00]
+        };
+        let mut greeting = vec![0x05, methods.len() as u8];
+        greeting.extend_from_slice(methods);
+        stream.write_all(&greeting)?;
+
+        let mut selection = [0; 2];
+        stream.read_exact(&mut selection)?;
+        if selection[0] != 0x05 {
+            return Err(Error::new(Kind::Proxy(None), "unexpected socks version from proxy."));
+        }
+
+        match selection[1] {
+            0x00 => {}
+            0x02 => {
+                if !self.has_auth() {
+                    return Err(Error::new(
+                        Kind::Proxy(None),
+                        "socks5 proxy requires auth, but dont have auth.",
+                    ));
+                }
+                let username = self.url.username();
+                let password = self.url.password().unwrap();
+                let mut negotiation = vec![0x01, username.len() as u8];
+                negotiation.extend_from_slice(username.as_bytes());
+                negotiation.push(password.len() as u8);
+                negotiation.extend_from_slice(password.as_bytes());
+                stream.write_all(&negotiation)?;
+
+                let mut status = [0; 2];
+                stream.read_exact(&mut status)?;
+                if status[1] != 0x00 {
+                    return Err(Error::new(Kind::Proxy(None), "socks5 proxy authentication failed."));
+                }
+            }
+            0xff => {
+                return Err(Error::new(
+                    Kind::Proxy(None),
+                    "socks5 proxy rejected all offered auth methods.",
+                ));
+            }
+            _ => {
+                return Err(Error::new(Kind::Proxy(None), "unexpected socks5 auth method selected."));
+            }
+        }
+
+        let host = url.host_str().unwrap();
+        let port = url.port_or_known_default().unwrap_or(80);
+
+        let mut request = vec![0x05, 0x01, 0x00, 0x03, host.len() as u8];
+        request.extend_from_slice(host.as_bytes());
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request)?;
+
+        let mut reply_head = [0; 4];
+        stream.read_exact(&mut reply_head)?;
+        if reply_head[0] != 0x05 {
+            return Err(Error::new(Kind::Proxy(None), "unexpected socks version from proxy."));
+        }
+        if reply_head[1] != 0x00 {
+            return Err(Error::new(Kind::Proxy(None), "socks5 proxy failed to establish connection."));
+        }
+
+        match reply_head[3] {
+            0x01 => {
+                let mut rest = [0; 6];
+                stream.read_exact(&mut rest)?;
+            }
+            0x03 => {
+                let mut len = [0; 1];
+                stream.read_exact(&mut len)?;
+                let mut rest = vec![0; len[0] as usize + 2];
+                stream.read_exact(&mut rest)?;
+            }
+            0x04 => {
+                let mut rest = [0; 18];
+                stream.read_exact(&mut rest)?;
+            }
+            _ => {
+                return Err(Error::new(Kind::Proxy(None), "unexpected socks5 address type in reply."));
+            }
+        }
+
+        Ok(ConnectOutcome {
+            stream: TcpStream::from_stream(stream)?,
+            response: None,
+        })
+    }
+
+    fn connect_socks4(&self, url: &Url) -> Result<ConnectOutcome> {
         let mut stream = StdTcpStream::connect(&self.url)?;
 
+        let host = url.host_str().unwrap();
+        let port = url.port_or_known_default().unwrap_or(80);
+        let user_id = if self.has_auth() {
+            self.url.username().to_string()
+        } else {
+            String::new()
+        };
+
+        let mut request = vec![0x04, 0x01];
+        request.extend_from_slice(&port.to_be_bytes());
+        request.extend_from_slice(&[0, 0, 0, 1]);
+        request.extend_from_slice(user_id.as_bytes());
+        request.push(0x00);
+        request.extend_from_slice(host.as_bytes());
+        request.push(0x00);
+        stream.write_all(&request)?;
+
+        let mut reply = [0; 8];
+        stream.read_exact(&mut reply)?;
+        if reply[1] != 0x5a {
+            return Err(Error::new(Kind::Proxy(None), "socks4 proxy failed to establish connection."));
+        }
+
+        Ok(ConnectOutcome {
+            stream: TcpStream::from_stream(stream)?,
+            response: None,
+        })
+    }
+
+    fn connect_http(&self, url: &Url) -> Result<ConnectOutcome> {
         let host = format!(
             "{}:{}",
             url.host_str().unwrap(),
             url.port_or_known_default().unwrap_or(80)
         );
+        self.connect_http_with_host(url, &host)
+    }
+
+    fn connect_http_with_host(&self, url: &Url, host: &str) -> Result<ConnectOutcome> {
+        if let AuthType::Digest = self.auth_type {
+            return self.connect_http_digest(host);
+        }
+
+        let mut stream = StdTcpStream::connect(&self.url)?;
+
         let connect = match self.auth_type {
             AuthType::None => format!(
                 "CONNECT {} HTTP/1.1\r\nHost: {}\r\nConnection: keep-alive\r\n\r\n",
@@ -104,42 +575,359 @@ impl Proxy {
                     ));
                 }
             }
-            AuthType::Digest => unimplemented!(),
+            AuthType::Bearer => match &self.token {
+                Some(token) => format!(
+                    "CONNECT {} HTTP/1.1\r\nHost: {}\r\nProxy-Authorization: Bearer {}\r\nConnection: keep-alive\r\n\r\n",
+                    host, host, self.auth_type.get_credential(token)
+                ),
+                None => {
+                    return Err(Error::new(
+                        Kind::Proxy(None),
+                        "use bearer auth, but dont have a token.",
+                    ));
+                }
+            },
+            AuthType::Digest => unreachable!(),
             AuthType::Unknown(_) => {
                 return Err(Error::new(Kind::Proxy(None), "unsupport authorization type."));
             }
         };
 
         debug!("{}", connect);
-        stream.write(connect.as_ref())?;
+        stream.write_all(connect.as_ref())?;
 
-        let mut buf = [0; 1024];
-
-        stream.read(&mut buf)?;
-
-        let mut headers = [EMPTY_HEADER; 32];
-        let mut res = Response::new(&mut headers);
-        res.parse(&mut buf)?;
+        let res = read_proxy_response(&mut stream)?;
 
         match res.code {
-            Some(code) if code >= 200 && code < 300 => Ok(TcpStream::from_stream(stream)?),
-            Some(code) if code == 401 => Err(Error::new(Kind::Proxy(None), "proxy unauthorized.")),
-            Some(code) if code == 407 => {
-                let auth_list = res
+            code if code >= 200 && code < 300 => Ok(ConnectOutcome {
+                stream: TcpStream::from_stream(stream)?,
+                response: Some(res),
+            }),
+            401 => Err(Error::new(Kind::Proxy(None), "proxy unauthorized.")),
+            407 => {
+                let auth_headers: Vec<(AuthType, String)> = res
                     .headers
                     .iter()
-                    .filter_map(|header| {
-                        if header.name != "Proxy-Authenticate" {
+                    .filter_map(|(name, value)| {
+                        if !name.eq_ignore_ascii_case("Proxy-Authenticate") {
                             return None;
-                        } else {
-                            let value = String::from_utf8(header.value.to_vec()).unwrap();
-                            return Some(AuthType::from(value.as_ref()));
                         }
+                        Some((AuthType::from(value.as_ref()), value.clone()))
                     })
-                    .collect::<Vec<AuthType>>();
+                    .collect();
+
+                if self.auto_auth && (self.has_auth() || self.token.is_some()) {
+                    return self.connect_with_strongest_auth(url, host, &auth_headers);
+                }
+
+                let auth_list = auth_headers.into_iter().map(|(auth_type, _)| auth_type).collect();
                 Err(Error::new(Kind::Proxy(Some(auth_list)), "proxy required authorization."))
             }
             _ => Err(Error::new(Kind::Proxy(None), "unexpect responsecode from proxy.")),
         }
     }
+
+    /// Picks the strongest scheme the proxy offered (Digest, then Basic, then
+    /// Bearer, skipping `AuthType::Unknown`) and retries the CONNECT with it,
+    /// reusing the already-threaded `host` and, for Digest, the challenge
+    /// already taken from `auth_headers` instead of opening a fresh connection to
+    /// ask for it again.
+    fn connect_with_strongest_auth(
+        &self,
+        url: &Url,
+        host: &str,
+        auth_headers: &[(AuthType, String)],
+    ) -> Result<ConnectOutcome> {
+        if self.has_auth() {
+            if let Some((_, challenge)) = auth_headers
+                .iter()
+                .find(|(auth_type, _)| matches!(auth_type, AuthType::Digest))
+            {
+                return self.connect_http_digest_with_challenge(host, challenge);
+            }
+
+            if auth_headers
+                .iter()
+                .any(|(auth_type, _)| matches!(auth_type, AuthType::Basic))
+            {
+                let mut proxy = self.clone();
+                proxy.set_auth_type(AuthType::Basic);
+                proxy.auto_auth = false;
+                return proxy.connect_http_with_host(url, host);
+            }
+        }
+
+        if self.token.is_some()
+            && auth_headers
+                .iter()
+                .any(|(auth_type, _)| matches!(auth_type, AuthType::Bearer))
+        {
+            let mut proxy = self.clone();
+            proxy.set_auth_type(AuthType::Bearer);
+            proxy.auto_auth = false;
+            return proxy.connect_http_with_host(url, host);
+        }
+
+        Err(Error::new(
+            Kind::Proxy(None),
+            "proxy offered no supported authorization type.",
+        ))
+    }
+
+    fn connect_http_digest(&self, host: &str) -> Result<ConnectOutcome> {
+        let mut stream = StdTcpStream::connect(&self.url)?;
+
+        let connect = format!(
+            "CONNECT {} HTTP/1.1\r\nHost: {}\r\nConnection: keep-alive\r\n\r\n",
+            host, host
+        );
+        debug!("{}", connect);
+        stream.write_all(connect.as_ref())?;
+
+        let res = read_proxy_response(&mut stream)?;
+
+        let challenge = match res.code {
+            code if code >= 200 && code < 300 => {
+                return Ok(ConnectOutcome {
+                    stream: TcpStream::from_stream(stream)?,
+                    response: Some(res),
+                })
+            }
+            407 => res
+                .headers
+                .into_iter()
+                .filter_map(|(name, value)| {
+                    if name.eq_ignore_ascii_case("Proxy-Authenticate") {
+                        Some(value)
+                    } else {
+                        None
+                    }
+                })
+                .find(|value| value.to_lowercase().starts_with("digest")),
+            _ => return Err(Error::new(Kind::Proxy(None), "unexpect responsecode from proxy.")),
+        };
+
+        let challenge = challenge
+            .ok_or_else(|| Error::new(Kind::Proxy(None), "proxy did not offer digest authentication."))?;
+
+        self.connect_http_digest_with_challenge(host, &challenge)
+    }
+
+    /// Sends the authenticated CONNECT for a `Proxy-Authenticate: Digest ...`
+    /// challenge that's already been parsed out of a prior response, instead
+    /// of reopening a connection to request one we already have.
+    fn connect_http_digest_with_challenge(&self, host: &str, challenge: &str) -> Result<ConnectOutcome> {
+        if !self.has_auth() {
+            return Err(Error::new(
+                Kind::Proxy(None),
+                "use digest auth, but dont have auth.",
+            ));
+        }
+
+        let params = parse_digest_challenge(challenge);
+        let authorization = build_digest_authorization(
+            self.url.username(),
+            self.url.password().unwrap(),
+            host,
+            &params,
+        )?;
+
+        let mut stream = StdTcpStream::connect(&self.url)?;
+        let connect = format!(
+            "CONNECT {} HTTP/1.1\r\nHost: {}\r\nProxy-Authorization: {}\r\nConnection: keep-alive\r\n\r\n",
+            host, host, authorization
+        );
+        debug!("{}", connect);
+        stream.write_all(connect.as_ref())?;
+
+        let res = read_proxy_response(&mut stream)?;
+
+        match res.code {
+            code if code >= 200 && code < 300 => Ok(ConnectOutcome {
+                stream: TcpStream::from_stream(stream)?,
+                response: Some(res),
+            }),
+            401 | 407 => Err(Error::new(Kind::Proxy(None), "digest authentication with proxy failed.")),
+            _ => Err(Error::new(Kind::Proxy(None), "unexpect responsecode from proxy.")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `Read` that hands back one pre-chunked slice per call, to exercise
+    /// `read_proxy_response`'s growable-buffer loop against a response split
+    /// across multiple short reads instead of arriving in one `read` call.
+    struct ChunkedReader {
+        chunks: std::collections::VecDeque<Vec<u8>>,
+    }
+
+    impl ChunkedReader {
+        fn new(chunks: &[&[u8]]) -> Self {
+            ChunkedReader {
+                chunks: chunks.iter().map(|chunk| chunk.to_vec()).collect(),
+            }
+        }
+    }
+
+    impl Read for ChunkedReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.chunks.pop_front() {
+                Some(mut chunk) => {
+                    if chunk.len() > buf.len() {
+                        let remainder = chunk.split_off(buf.len());
+                        self.chunks.push_front(remainder);
+                    }
+                    let n = chunk.len();
+                    buf[..n].copy_from_slice(&chunk);
+                    Ok(n)
+                }
+                None => Ok(0),
+            }
+        }
+    }
+
+    #[test]
+    fn reads_a_response_split_across_short_reads() {
+        let mut reader = ChunkedReader::new(&[
+            b"HTTP/1.1 200 ",
+            b"Connection Established\r\n",
+            b"Proxy-Agent: test\r\n",
+            b"\r\n",
+        ]);
+
+        let response = read_proxy_response(&mut reader).unwrap();
+
+        assert_eq!(response.code, 200);
+        assert!(response
+            .headers
+            .iter()
+            .any(|(name, value)| name == "Proxy-Agent" && value == "test"));
+    }
+
+    #[test]
+    fn errors_when_proxy_closes_before_a_complete_response() {
+        let mut reader = ChunkedReader::new(&[b"HTTP/1.1 200 Connection Established\r\n"]);
+
+        assert!(read_proxy_response(&mut reader).is_err());
+    }
+
+    #[test]
+    fn errors_when_response_exceeds_the_size_limit() {
+        // A well-formed but never-terminated header, so parsing stays
+        // `Partial` until the accumulated size crosses the limit.
+        let mut oversized = b"HTTP/1.1 200 OK\r\nX-Filler: ".to_vec();
+        oversized.resize(MAX_PROXY_RESPONSE_BYTES + 1, b'a');
+        let mut reader = ChunkedReader::new(&[&oversized]);
+
+        assert!(read_proxy_response(&mut reader).is_err());
+    }
+
+    #[test]
+    fn md5_hex_matches_known_vectors() {
+        assert_eq!(md5_hex(b""), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(md5_hex(b"abc"), "900150983cd24fb0d6963f7d28e17f72");
+    }
+
+    #[test]
+    fn parses_digest_challenge_fields() {
+        let challenge = "Digest realm=\"testrealm@host.com\", \
+                          qop=\"auth,auth-int\", \
+                          nonce=\"dcd98b7102dd2f0e8b11d0f600bfb0c093\", \
+                          opaque=\"5ccc069c403ebaf9f0171e9517f40e41\"";
+
+        let params = parse_digest_challenge(challenge);
+
+        assert_eq!(params.realm, "testrealm@host.com");
+        assert_eq!(params.nonce, "dcd98b7102dd2f0e8b11d0f600bfb0c093");
+        assert_eq!(params.qop.as_deref(), Some("auth,auth-int"));
+        assert_eq!(params.opaque.as_deref(), Some("5ccc069c403ebaf9f0171e9517f40e41"));
+        assert!(params.algorithm.is_none());
+    }
+
+    #[test]
+    fn builds_digest_authorization_without_qop() {
+        let params = DigestParams {
+            realm: "testrealm@host.com".to_string(),
+            nonce: "dcd98b7102dd2f0e8b11d0f600bfb0c093".to_string(),
+            qop: None,
+            opaque: None,
+            algorithm: None,
+        };
+
+        let header = build_digest_authorization(
+            "Mufasa",
+            "Circle Of Life",
+            "/dir/index.html",
+            &params,
+        )
+        .unwrap();
+
+        // No qop offered, so the response hash is deterministic:
+        // MD5(MD5("Mufasa:testrealm@host.com:Circle Of Life") : nonce : MD5("CONNECT:/dir/index.html"))
+        assert!(header.contains("response=\"517f5ac0e5ba49522d61fe8da56267e8\""));
+        assert!(!header.contains("qop="));
+    }
+
+    #[test]
+    fn builds_digest_authorization_with_auth_qop() {
+        let params = DigestParams {
+            realm: "testrealm@host.com".to_string(),
+            nonce: "dcd98b7102dd2f0e8b11d0f600bfb0c093".to_string(),
+            qop: Some("auth".to_string()),
+            opaque: Some("5ccc069c403ebaf9f0171e9517f40e41".to_string()),
+            algorithm: None,
+        };
+
+        let header =
+            build_digest_authorization("Mufasa", "Circle Of Life", "/dir/index.html", &params).unwrap();
+
+        assert!(header.contains("qop=auth"));
+        assert!(header.contains("nc=00000001"));
+        assert!(header.contains("opaque=\"5ccc069c403ebaf9f0171e9517f40e41\""));
+    }
+
+    #[test]
+    fn rejects_qop_that_only_offers_auth_int() {
+        let params = DigestParams {
+            realm: "testrealm@host.com".to_string(),
+            nonce: "dcd98b7102dd2f0e8b11d0f600bfb0c093".to_string(),
+            qop: Some("auth-int".to_string()),
+            opaque: None,
+            algorithm: None,
+        };
+
+        assert!(build_digest_authorization("Mufasa", "Circle Of Life", "/dir/index.html", &params).is_err());
+    }
+
+    #[test]
+    fn accepts_auth_token_among_other_qop_options() {
+        let params = DigestParams {
+            realm: "testrealm@host.com".to_string(),
+            nonce: "dcd98b7102dd2f0e8b11d0f600bfb0c093".to_string(),
+            qop: Some("auth-int,auth".to_string()),
+            opaque: None,
+            algorithm: None,
+        };
+
+        let header =
+            build_digest_authorization("Mufasa", "Circle Of Life", "/dir/index.html", &params).unwrap();
+
+        assert!(header.contains("qop=auth"));
+    }
+
+    #[test]
+    fn rejects_unsupported_digest_algorithm() {
+        let params = DigestParams {
+            realm: "testrealm@host.com".to_string(),
+            nonce: "dcd98b7102dd2f0e8b11d0f600bfb0c093".to_string(),
+            qop: None,
+            opaque: None,
+            algorithm: Some("MD5-sess".to_string()),
+        };
+
+        assert!(build_digest_authorization("Mufasa", "Circle Of Life", "/dir/index.html", &params).is_err());
+    }
 }